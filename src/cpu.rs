@@ -1,19 +1,67 @@
+#[cfg(feature = "std")]
 use std::fs::File;
+#[cfg(feature = "std")]
 use std::path::Path;
+
+#[cfg(feature = "std")]
 use std::cmp::max;
+#[cfg(not(feature = "std"))]
+use core::cmp::max;
+
+#[cfg(feature = "std")]
 use std::ops::{Deref, DerefMut};
+#[cfg(not(feature = "std"))]
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(not(feature = "std"))]
+use core::fmt;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use alloc::boxed::Box;
+
+use device::Device;
+
+#[cfg(feature = "std")]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// The amount of memory given to an image when the caller doesn't ask for a
+/// specific size: 4MB, or the size of the image itself, whichever is larger.
+const DEFAULT_MEMORY_SIZE: usize = 1024 * 1024;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+/// Decodes an already-open little-endian i32 image stream, the same layout
+/// `Memory::new` reads off disk. Hosts that already hold the image in memory
+/// (fetched over a network, bundled as a static array, ...) can read it
+/// through a `Cursor` and skip the filesystem entirely.
+#[cfg(feature = "std")]
+pub fn decode_image<R: ReadBytesExt>(reader: &mut R) -> Vec<i32> {
+    let mut cells = Vec::new();
+    loop {
+        match reader.read_i32::<LittleEndian>() {
+            Ok(x) => cells.push(x),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => panic!("{}", e),
+        }
+    }
+    cells
+}
+
+/// Reads one little-endian i32 cell, panicking on a short read the same
+/// way `decode_image` does for anything other than a clean EOF.
+#[cfg(feature = "std")]
+fn read_cell<R: ReadBytesExt>(reader: &mut R) -> i32 {
+    match reader.read_i32::<LittleEndian>() {
+        Ok(x) => x,
+        Err(e) => panic!("{}", e),
+    }
+}
 
 use self::Action::*;
 
 macro_rules! get_memory {
-    ($foo: ident, $addr: expr) => {
-        match $foo.memory.memory_space.get($addr as usize) {
-            Some(&x) => x,
-            None => return None,
-        }
-    };
     ($foo: ident, $addr: expr, $rval: expr) => {
         match $foo.memory.memory_space.get($addr as usize) {
             Some(&x) => x,
@@ -22,33 +70,63 @@ macro_rules! get_memory {
     };
 }
 
+/// A trap raised by the CPU when it cannot continue executing the current
+/// image. The `ip` is left pointing at the faulting instruction, so a host
+/// can inspect `get_info()` (and the data stack, via `ports_and_stack()`) before
+/// deciding whether to abort or reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DataStackUnderflow,
+    AddressStackUnderflow,
+    MemoryOutOfBounds { addr: i32 },
+    DivideByZero,
+    InvalidOpcode,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::DataStackUnderflow => write!(f, "data stack underflow"),
+            Fault::AddressStackUnderflow => write!(f, "address stack underflow"),
+            Fault::MemoryOutOfBounds { addr } => write!(f, "memory access out of bounds: {}", addr),
+            Fault::DivideByZero => write!(f, "divide by zero"),
+            Fault::InvalidOpcode => write!(f, "invalid opcode"),
+        }
+    }
+}
+
 struct Memory {
     data_stack: Vec<i32>,
     address_stack: Vec<i32>,
     memory_space: Vec<i32>,
+    image_size: i32,
 }
 
 impl Memory {
+    #[cfg(feature = "std")]
     fn new(path: &Path) -> Memory {
         let mut file = File::open(path).unwrap();
         let size = file.metadata().unwrap().len() / 4;
-        let mut memory = Vec::with_capacity(max(size as usize, 1024*1024)); // 4MB or image size
-
-        loop {
-            match file.read_i32::<LittleEndian>() {
-                Ok(x) => memory.push(x),
-                Err(::byteorder::Error::UnexpectedEOF) => break,
-                Err(e) => panic!(e),
-            }
-        }
+        let cells = decode_image(&mut file);
+        Memory::from_cells(&cells, max(size as usize, DEFAULT_MEMORY_SIZE))
+    }
 
-        while memory.capacity() > memory.len() {
+    /// Builds a `Memory` from an already-decoded image, zero-padded up to
+    /// `min_size` cells (or the image length, whichever is larger). This is
+    /// the `alloc`-only entry point: it never touches the filesystem, so it
+    /// works in `no_std` and WASM hosts that hand the VM an image they
+    /// loaded themselves.
+    fn from_cells(image: &[i32], min_size: usize) -> Memory {
+        let mut memory = Vec::with_capacity(max(image.len(), min_size));
+        memory.extend_from_slice(image);
+        while memory.len() < memory.capacity() {
             memory.push(0);
         }
 
         Memory {
             data_stack: Vec::new(),
             address_stack: Vec::new(),
+            image_size: image.len() as i32,
             memory_space: memory
         }
     }
@@ -81,20 +159,145 @@ pub struct Info {
     pub memory_size: i32,
     pub data_stack_depth: i32,
     pub address_stack_depth: i32,
+    pub image_size: i32,
 }
 
 pub struct CPU {
     memory: Memory,
     ip: i32,
-    ports: [i32; 12]
+    ports: [i32; 12],
+    devices: Vec<(usize, Box<Device>)>,
 }
 
 impl CPU {
+    #[cfg(feature = "std")]
     pub fn new(path: &Path) -> CPU {
         CPU {
             memory: Memory::new(path),
             ip: 0,
             ports: [0; 12],
+            devices: Vec::new(),
+        }
+    }
+
+    /// Builds a `CPU` straight from an already-decoded image, with no
+    /// dependency on `std::fs`. Memory is sized to `DEFAULT_MEMORY_SIZE`
+    /// cells (or the image length, whichever is larger), the same default
+    /// `new` uses for images loaded from disk.
+    pub fn from_image(image: &[i32]) -> CPU {
+        CPU {
+            memory: Memory::from_cells(image, DEFAULT_MEMORY_SIZE),
+            ip: 0,
+            ports: [0; 12],
+            devices: Vec::new(),
+        }
+    }
+
+    /// Binds `device` to `port`, replacing whatever was previously bound
+    /// there. When the image `WAIT`s with a nonzero value sitting in
+    /// `port`, the CPU calls straight into the device instead of returning
+    /// control to the host.
+    pub fn register_device(&mut self, port: usize, device: Box<Device>) {
+        self.devices.retain(|&(p, _)| p != port);
+        self.devices.push((port, device));
+    }
+
+    /// Looks for every port (other than 0, the handshake port) holding a
+    /// nonzero value with a device bound to it, and dispatches to all of
+    /// them, since a single `WAIT` can be servicing several ports an image
+    /// set before waiting. Returns `false` if no triggered port had a
+    /// device bound, in which case the WAIT falls through to the host as
+    /// before.
+    fn dispatch_device(&mut self) -> bool {
+        let triggered: Vec<usize> = self.ports.iter().enumerate().skip(1)
+            .filter(|&(_, &v)| v != 0)
+            .map(|(i, _)| i)
+            .filter(|port| self.devices.iter().any(|&(p, _)| p == *port))
+            .collect();
+        if triggered.is_empty() {
+            return false;
+        }
+        let address_stack_depth = self.memory.address_stack.len() as i32;
+        let image_size = self.memory.image_size;
+        for port in triggered {
+            let index = self.devices.iter().position(|&(p, _)| p == port).unwrap();
+            self.devices[index].1.handle(&mut self.ports, &mut self.memory.data_stack, &mut self.memory.memory_space, address_stack_depth, image_size);
+            self.ports[port] = 0;
+        }
+        self.ports[0] = 1;
+        true
+    }
+
+    /// Dumps the complete execution state — memory, both stacks, `ip` and
+    /// the ports — to `path`, in the same little-endian cell layout images
+    /// are loaded in. A small header up front records how many cells each
+    /// of memory/data stack/address stack hold, so `load_snapshot` can size
+    /// its reads without guessing. This is the "save image" half of the
+    /// Ngaro/Retro checkpoint model: a Forth image can trigger its own
+    /// persistence through the capability/file devices, and a host can
+    /// resume the exact same run later with `load_snapshot`.
+    #[cfg(feature = "std")]
+    pub fn save_snapshot(&self, path: &Path) {
+        let mut file = File::create(path).unwrap();
+        file.write_i32::<LittleEndian>(self.memory.memory_space.len() as i32).unwrap();
+        file.write_i32::<LittleEndian>(self.memory.data_stack.len() as i32).unwrap();
+        file.write_i32::<LittleEndian>(self.memory.address_stack.len() as i32).unwrap();
+        file.write_i32::<LittleEndian>(self.memory.image_size).unwrap();
+        file.write_i32::<LittleEndian>(self.ip).unwrap();
+        for &port in self.ports.iter() {
+            file.write_i32::<LittleEndian>(port).unwrap();
+        }
+        for &cell in self.memory.memory_space.iter() {
+            file.write_i32::<LittleEndian>(cell).unwrap();
+        }
+        for &cell in self.memory.data_stack.iter() {
+            file.write_i32::<LittleEndian>(cell).unwrap();
+        }
+        for &cell in self.memory.address_stack.iter() {
+            file.write_i32::<LittleEndian>(cell).unwrap();
+        }
+    }
+
+    /// Restores a `CPU` from a file written by `save_snapshot`. Registered
+    /// devices are not part of the snapshot — the host re-registers
+    /// whatever devices the resumed run needs.
+    #[cfg(feature = "std")]
+    pub fn load_snapshot(path: &Path) -> CPU {
+        let mut file = File::open(path).unwrap();
+        let memory_len = read_cell(&mut file) as usize;
+        let data_len = read_cell(&mut file) as usize;
+        let address_len = read_cell(&mut file) as usize;
+        let image_size = read_cell(&mut file);
+        let ip = read_cell(&mut file);
+
+        let mut ports = [0; 12];
+        for port in ports.iter_mut() {
+            *port = read_cell(&mut file);
+        }
+
+        let mut memory_space = Vec::with_capacity(memory_len);
+        for _ in 0..memory_len {
+            memory_space.push(read_cell(&mut file));
+        }
+        let mut data_stack = Vec::with_capacity(data_len);
+        for _ in 0..data_len {
+            data_stack.push(read_cell(&mut file));
+        }
+        let mut address_stack = Vec::with_capacity(address_len);
+        for _ in 0..address_len {
+            address_stack.push(read_cell(&mut file));
+        }
+
+        CPU {
+            memory: Memory {
+                data_stack: data_stack,
+                address_stack: address_stack,
+                memory_space: memory_space,
+                image_size: image_size,
+            },
+            ip: ip,
+            ports: ports,
+            devices: Vec::new(),
         }
     }
 
@@ -109,191 +312,264 @@ impl CPU {
             memory_size: self.memory.memory_space.len() as i32,
             data_stack_depth: self.memory.data_stack.len() as i32,
             address_stack_depth: self.memory.address_stack.len() as i32,
+            image_size: self.memory.image_size,
         }
     }
 
-    pub fn pop_data(&mut self) -> i32 {
-        self.memory.data_stack.pop().expect("Data stack underflow.")
+    pub fn pop_data(&mut self) -> Result<i32, Fault> {
+        self.memory.data_stack.pop().ok_or(Fault::DataStackUnderflow)
     }
 
     pub fn push_data(&mut self, data: i32) {
         self.memory.data_stack.push(data)
     }
 
-    fn pop_address(&mut self) -> i32 {
-        self.memory.address_stack.pop().expect("Address stack underflow.")
+    fn pop_address(&mut self) -> Result<i32, Fault> {
+        self.memory.address_stack.pop().ok_or(Fault::AddressStackUnderflow)
     }
 
     fn push_address(&mut self, data: i32) {
         self.memory.address_stack.push(data)
     }
 
-    fn jump(&mut self) {
-        self.ip += 1;
-        self.ip = get_memory!(self, self.ip, panic!("Jump out of bounds.")) - 1;
+    fn jump(&mut self) -> Result<(), Fault> {
+        let opcode_addr = self.ip;
+        let target_addr = self.ip + 1;
+        match self.memory.memory_space.get(target_addr as usize) {
+            Some(&addr) => {
+                self.ip = addr.wrapping_sub(1);
+                Ok(())
+            }
+            None => {
+                self.ip = opcode_addr;
+                Err(Fault::MemoryOutOfBounds { addr: target_addr })
+            }
+        }
     }
 
-    fn cond_stack_jump<F>(&mut self, cond: F)
+    fn cond_stack_jump<F>(&mut self, cond: F) -> Result<(), Fault>
         where F: Fn(i32, i32) -> bool
     {
-        let (a, b) = (self.pop_data(), self.pop_data());
-        if cond(a,b) { self.jump() } else { self.ip += 1; }
+        let (a, b) = (try!(self.pop_data()), try!(self.pop_data()));
+        if cond(a,b) { try!(self.jump()); } else { self.ip = self.ip.wrapping_add(1); }
+        Ok(())
     }
 
-    fn pop_2_push_1<F>(&mut self, func: F)
+    fn pop_2_push_1<F>(&mut self, func: F) -> Result<(), Fault>
         where F: FnOnce(i32, i32) -> i32
     {
-        let (a, b) = (self.pop_data(), self.pop_data());
+        let (a, b) = (try!(self.pop_data()), try!(self.pop_data()));
         self.push_data(func(a,b));
+        Ok(())
     }
 
-    pub fn next(&mut self) -> Option<Action> {
+    pub fn next(&mut self) -> Result<Option<Action>, Fault> {
         loop {
-            let instruction = get_memory!(self, self.ip);
-            match instruction {
-                0 => { } // NOP
-                1 => { // LIT X
-                    self.ip += 1;
-                    let data = get_memory!(self, self.ip);
-                    self.push_data(data);
-                }
-                2 => { // DUP
-                    let item = self.pop_data();
-                    self.push_data(item);
-                    self.push_data(item);
-                }
-                3 => { // DROP
-                    self.pop_data();
-                }
-                4 => { // SWAP
-                    let (a, b) = (self.pop_data(), self.pop_data());
-                    self.push_data(a);
-                    self.push_data(b);
-                }
-                5 => { // PUSH
-                    let data = self.pop_data();
-                    self.push_address(data);
-                }
-                6 => { // POP
-                    let data = self.pop_address();
-                    self.push_data(data);
-                }
-                7 => { // LOOP A
-                    let mut data = self.pop_data();
-                    data -= 1;
-                    if data > 0 {
-                        self.jump();
-                        self.push_data(data);
-                    } else {
-                        self.ip += 1;
-                    }
-                }
-                8 => { // JUMP A
-                    self.jump();
-                }
-                9 => { // RETURN
-                    let addr = self.pop_address();
-                    self.ip = addr;
-                }
-                10 => { // GT_JUMP
-                    self.cond_stack_jump(|a, b| b > a);
-                }
-                11 => { // LT_JUMP
-                    self.cond_stack_jump(|a, b| b < a);
-                }
-                12 => { // NE_JUMP
-                    self.cond_stack_jump(|a, b| a != b);
-                }
-                13 => { // EQ_JUMP
-                    self.cond_stack_jump(|a, b| a == b);
-                }
-                14 => { // FETCH
-                    let addr = self.pop_data();
-                    let data = *self.memory.memory_space.get(addr as usize).expect("FETCH beyond bounds.");
+            match try!(self.step()) {
+                StepControl::Continue => { }
+                StepControl::Wait => return Ok(Some(Wait)),
+                StepControl::Halt => return Ok(None),
+            }
+        }
+    }
+
+    /// Executes at most `budget` instructions, stopping early on `WAIT` or
+    /// when the instruction pointer runs off the end of memory. This is the
+    /// cooperative-scheduling "timer" an embedder can use to slice VM time
+    /// across many instances without threads — `StepOutcome` always reports
+    /// how much of the budget is left, so a host can resume later with
+    /// whatever remains.
+    pub fn run_for(&mut self, budget: u64) -> Result<StepOutcome, Fault> {
+        let mut remaining = budget;
+        while remaining > 0 {
+            match try!(self.step()) {
+                StepControl::Continue => { remaining -= 1; }
+                StepControl::Wait => return Ok(StepOutcome::Wait { remaining: remaining }),
+                StepControl::Halt => return Ok(StepOutcome::Halted { remaining: remaining }),
+            }
+        }
+        Ok(StepOutcome::BudgetExhausted)
+    }
+
+    /// Executes a single instruction, reporting whether the dispatch loop
+    /// should keep going, the image asked to `WAIT`, or `ip` ran off the
+    /// end of memory.
+    fn step(&mut self) -> Result<StepControl, Fault> {
+        let instruction = get_memory!(self, self.ip, Ok(StepControl::Halt));
+        match instruction {
+            0 => { } // NOP
+            1 => { // LIT X
+                self.ip = self.ip.wrapping_add(1);
+                let data = get_memory!(self, self.ip, Ok(StepControl::Halt));
+                self.push_data(data);
+            }
+            2 => { // DUP
+                let item = try!(self.pop_data());
+                self.push_data(item);
+                self.push_data(item);
+            }
+            3 => { // DROP
+                try!(self.pop_data());
+            }
+            4 => { // SWAP
+                let (a, b) = (try!(self.pop_data()), try!(self.pop_data()));
+                self.push_data(a);
+                self.push_data(b);
+            }
+            5 => { // PUSH
+                let data = try!(self.pop_data());
+                self.push_address(data);
+            }
+            6 => { // POP
+                let data = try!(self.pop_address());
+                self.push_data(data);
+            }
+            7 => { // LOOP A
+                let mut data = try!(self.pop_data());
+                data = data.wrapping_sub(1);
+                if data > 0 {
+                    try!(self.jump());
                     self.push_data(data);
+                } else {
+                    self.ip = self.ip.wrapping_add(1);
                 }
-                15 => { // STORE
-                    let (addr, data) = (self.pop_data(), self.pop_data());
-                    *self.memory.memory_space.get_mut(addr as usize).expect("STORE beyond bounds.") = data;
-                }
-                16 => { // ADD
-                    self.pop_2_push_1(|a, b| a+b);
-                }
-                17 => { // SUBTRACT
-                    self.pop_2_push_1(|a, b| b-a);
-                }
-                18 => { // MULTIPLY
-                    self.pop_2_push_1(|a, b| a*b);
-                }
-                19 => { // DIVMOD
-                    let (a, b) = (self.pop_data(), self.pop_data());
-                    self.push_data(b % a);
-                    self.push_data(b / a);
-                }
-                20 => { // AND
-                    self.pop_2_push_1(|a, b| a&b);
-                }
-                21 => { // OR
-                    self.pop_2_push_1(|a, b| a|b);
-                }
-                22 => { // XOR
-                    self.pop_2_push_1(|a, b| a^b);
-                }
-                23 => { // SHL
-                    self.pop_2_push_1(|a, b| b<<(a as usize));
-                }
-                24 => { // SHR
-                    self.pop_2_push_1(|a, b| (b as u32>>(a as usize)) as i32);
-                }
-                25 => { // ZERO_EXIT
-                    let data = self.pop_data();
-                    if data == 0 {
-                        self.ip = self.pop_address();
-                    } else {
-                        self.push_data(data);
-                    }
-                }
-                26 => { // INC
-                    let data = self.pop_data();
-                    self.push_data(data+1);
+            }
+            8 => { // JUMP A
+                try!(self.jump());
+            }
+            9 => { // RETURN
+                let addr = try!(self.pop_address());
+                self.ip = addr;
+            }
+            10 => { // GT_JUMP
+                try!(self.cond_stack_jump(|a, b| b > a));
+            }
+            11 => { // LT_JUMP
+                try!(self.cond_stack_jump(|a, b| b < a));
+            }
+            12 => { // NE_JUMP
+                try!(self.cond_stack_jump(|a, b| a != b));
+            }
+            13 => { // EQ_JUMP
+                try!(self.cond_stack_jump(|a, b| a == b));
+            }
+            14 => { // FETCH
+                let addr = try!(self.pop_data());
+                let data = get_memory!(self, addr, Err(Fault::MemoryOutOfBounds { addr: addr }));
+                self.push_data(data);
+            }
+            15 => { // STORE
+                let (addr, data) = (try!(self.pop_data()), try!(self.pop_data()));
+                match self.memory.memory_space.get_mut(addr as usize) {
+                    Some(cell) => *cell = data,
+                    None => return Err(Fault::MemoryOutOfBounds { addr: addr }),
                 }
-                27 => { // DEC
-                    let data = self.pop_data();
-                    self.push_data(data-1);
+            }
+            16 => { // ADD
+                try!(self.pop_2_push_1(|a, b| a.wrapping_add(b)));
+            }
+            17 => { // SUBTRACT
+                try!(self.pop_2_push_1(|a, b| b.wrapping_sub(a)));
+            }
+            18 => { // MULTIPLY
+                try!(self.pop_2_push_1(|a, b| a.wrapping_mul(b)));
+            }
+            19 => { // DIVMOD
+                let (a, b) = (try!(self.pop_data()), try!(self.pop_data()));
+                if a == 0 {
+                    return Err(Fault::DivideByZero);
                 }
-                28 => { // IN
-                    let port = self.pop_data();
-                    let data = self.ports.get(port as usize).map_or(0, |&x| x);
+                self.push_data(b.wrapping_rem(a));
+                self.push_data(b.wrapping_div(a));
+            }
+            20 => { // AND
+                try!(self.pop_2_push_1(|a, b| a&b));
+            }
+            21 => { // OR
+                try!(self.pop_2_push_1(|a, b| a|b));
+            }
+            22 => { // XOR
+                try!(self.pop_2_push_1(|a, b| a^b));
+            }
+            23 => { // SHL
+                try!(self.pop_2_push_1(|a, b| b.wrapping_shl(a as u32)));
+            }
+            24 => { // SHR
+                try!(self.pop_2_push_1(|a, b| (b as u32).wrapping_shr(a as u32) as i32));
+            }
+            25 => { // ZERO_EXIT
+                let data = try!(self.pop_data());
+                if data == 0 {
+                    self.ip = try!(self.pop_address());
+                } else {
                     self.push_data(data);
-                    self.ports.get_mut(port as usize).map(|x| *x = 0);
-                }
-                29 => { // OUT
-                    let (port, data) = (self.pop_data(), self.pop_data());
-                    self.ports.get_mut(port as usize).map(|x| *x = data);
-                }
-                30 => { // WAIT
-                    if self.ports.get(0).map_or(false, |&x| x == 0) {
-                        return Some(Wait);
-                    }
                 }
-                x => { // Implicit call
-                    let ip = self.ip;
-                    self.push_address(ip);
-                    self.ip = x - 1;
+            }
+            26 => { // INC
+                let data = try!(self.pop_data());
+                self.push_data(data.wrapping_add(1));
+            }
+            27 => { // DEC
+                let data = try!(self.pop_data());
+                self.push_data(data.wrapping_sub(1));
+            }
+            28 => { // IN
+                let port = try!(self.pop_data());
+                let data = self.ports.get(port as usize).map_or(0, |&x| x);
+                self.push_data(data);
+                self.ports.get_mut(port as usize).map(|x| *x = 0);
+            }
+            29 => { // OUT
+                let (port, data) = (try!(self.pop_data()), try!(self.pop_data()));
+                self.ports.get_mut(port as usize).map(|x| *x = data);
+            }
+            30 => { // WAIT
+                if self.ports.get(0).map_or(false, |&x| x == 0) && !self.dispatch_device() {
+                    return Ok(StepControl::Wait);
                 }
-            };
-            self.ip += 1;
-        }
+            }
+            x if x < 0 => {
+                return Err(Fault::InvalidOpcode);
+            }
+            x => { // Implicit call
+                let ip = self.ip;
+                self.push_address(ip);
+                self.ip = x - 1;
+            }
+        };
+        self.ip = self.ip.wrapping_add(1);
+        Ok(StepControl::Continue)
     }
 }
 
+#[derive(Debug)]
 pub enum Action {
     Empty,
     Wait,
 }
 
+/// The result of executing a single instruction via `step()`.
+enum StepControl {
+    Continue,
+    Wait,
+    Halt,
+}
+
+/// The result of `run_for`: whether the budget ran out, the image asked to
+/// `WAIT`, or `ip` ran off the end of memory. The last two variants report
+/// how much of the budget was left unused, so a host driving many VM
+/// instances round-robin knows how to account for the next slice.
+pub enum StepOutcome {
+    BudgetExhausted,
+    Wait { remaining: u64 },
+    Halted { remaining: u64 },
+}
+
 #[allow(dead_code)]
 mod debug {
+    #[cfg(not(feature = "std"))]
+    use alloc::vec::Vec;
+
     pub fn opcode_to_name(opcode: i32) -> &'static str {
         const NAMES: &'static [&'static str] = &[
             "NOP",
@@ -306,8 +582,8 @@ mod debug {
             "LOOP",
             "JUMP",
             "RETURN",
-            "LT_JUMP",
             "GT_JUMP",
+            "LT_JUMP",
             "NE_JUMP",
             "EQ_JUMP",
             "FETCH",
@@ -330,4 +606,221 @@ mod debug {
                 ];
         NAMES.get(opcode as usize).map_or("CALL", |&x| x)
     }
+
+    /// One decoded instruction from `disassemble`: its address, mnemonic,
+    /// the operand cell it consumed (if any), and — for branches and calls
+    /// — the address it resolves to.
+    #[cfg(feature = "disasm")]
+    pub struct DisasmLine {
+        pub address: i32,
+        pub mnemonic: &'static str,
+        pub operand: Option<i32>,
+        pub target: Option<i32>,
+    }
+
+    #[cfg(feature = "disasm")]
+    impl super::fmt::Display for DisasmLine {
+        fn fmt(&self, f: &mut super::fmt::Formatter) -> super::fmt::Result {
+            match self.target {
+                Some(target) => write!(f, "{}: {} {}", self.address, self.mnemonic, target),
+                None => match self.operand {
+                    Some(operand) => write!(f, "{}: {} {}", self.address, self.mnemonic, operand),
+                    None => write!(f, "{}: {}", self.address, self.mnemonic),
+                },
+            }
+        }
+    }
+
+    /// Walks an image linearly and decodes it into `DisasmLine`s, the way a
+    /// debugger front-end would want it: `LIT <n>` folds its literal operand
+    /// into one line, `JUMP`/`LOOP`/the conditional jumps resolve their
+    /// target address, and opcodes above the instruction set (implicit
+    /// calls) are rendered as `CALL <addr>`.
+    ///
+    /// This is a purely static walk — it does not simulate the stacks, so a
+    /// `LIT`/jump operand that lands past the end of the image is rendered
+    /// without an operand rather than faulted.
+    #[cfg(feature = "disasm")]
+    pub fn disassemble(image: &[i32]) -> Vec<DisasmLine> {
+        let mut lines = Vec::new();
+        let mut addr = 0usize;
+        while addr < image.len() {
+            let opcode = image[addr];
+            let mnemonic = opcode_to_name(opcode);
+            let (operand, target, width) = match opcode {
+                1 => { // LIT <n>
+                    let operand = image.get(addr + 1).cloned();
+                    let width = if operand.is_some() { 2 } else { 1 };
+                    (operand, None, width)
+                }
+                7 | 8 | 10 | 11 | 12 | 13 => { // LOOP/JUMP/GT_JUMP/LT_JUMP/NE_JUMP/EQ_JUMP <addr>
+                    let target = image.get(addr + 1).cloned();
+                    let width = if target.is_some() { 2 } else { 1 };
+                    (target, target, width)
+                }
+                _ if mnemonic == "CALL" => (None, Some(opcode), 1),
+                _ => (None, None, 1),
+            };
+            lines.push(DisasmLine {
+                address: addr as i32,
+                mnemonic: mnemonic,
+                operand: operand,
+                target: target,
+            });
+            addr += width;
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_stack_underflow_faults_instead_of_panicking() {
+        let mut cpu = CPU::from_image(&[3]); // DROP, with nothing pushed
+        match cpu.next() {
+            Err(Fault::DataStackUnderflow) => { }
+            other => panic!("expected DataStackUnderflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn divide_by_zero_faults_instead_of_panicking() {
+        let mut cpu = CPU::from_image(&[1, 5, 1, 0, 19]); // LIT 5, LIT 0, DIVMOD
+        match cpu.next() {
+            Err(Fault::DivideByZero) => { }
+            other => panic!("expected DivideByZero, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn jump_to_i32_min_wraps_instead_of_overflowing() {
+        let mut cpu = CPU::from_image(&[8, i32::MIN]); // JUMP i32::MIN
+        assert!(cpu.step().is_ok());
+    }
+
+    #[test]
+    fn shl_with_an_out_of_range_amount_wraps_instead_of_panicking() {
+        let mut cpu = CPU::from_image(&[1, 5, 1, 40, 23]); // LIT 5, LIT 40, SHL
+        for _ in 0..3 {
+            assert!(cpu.step().is_ok());
+        }
+    }
+
+    #[test]
+    fn loop_with_i32_min_counter_wraps_instead_of_panicking() {
+        let mut cpu = CPU::from_image(&[1, i32::MIN, 7, 0]); // LIT i32::MIN, LOOP 0
+        for _ in 0..2 {
+            assert!(cpu.step().is_ok());
+        }
+    }
+
+    #[test]
+    fn decode_image_reads_little_endian_cells_to_a_clean_eof() {
+        let bytes: Vec<u8> = vec![1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+        let mut cursor = ::std::io::Cursor::new(bytes);
+        assert_eq!(decode_image(&mut cursor), vec![1, -1]);
+    }
+
+    #[cfg(feature = "disasm")]
+    #[test]
+    fn disassemble_folds_lit_operand_and_resolves_jump_targets() {
+        use super::debug::disassemble;
+
+        let image = [1, 42, 8, 0]; // LIT 42, JUMP 0
+        let lines = disassemble(&image);
+
+        assert_eq!(lines[0].mnemonic, "LIT");
+        assert_eq!(lines[0].operand, Some(42));
+        assert_eq!(lines[0].target, None);
+
+        assert_eq!(lines[1].mnemonic, "JUMP");
+        assert_eq!(lines[1].target, Some(0));
+    }
+
+    #[test]
+    fn run_for_stops_at_the_budget_with_instructions_left_to_run() {
+        let mut cpu = CPU::from_image(&[0, 0, 0, 0, 0]); // NOPs
+        match cpu.run_for(3) {
+            Ok(StepOutcome::BudgetExhausted) => { }
+            other => panic!("expected BudgetExhausted, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn run_for_reports_wait_and_the_unused_budget() {
+        let mut cpu = CPU::from_image(&[30]); // WAIT, no port triggered
+        match cpu.run_for(10) {
+            Ok(StepOutcome::Wait { remaining }) => assert_eq!(remaining, 10),
+            other => panic!("expected Wait, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    struct CountingDevice {
+        hits: ::std::rc::Rc<::std::cell::Cell<i32>>,
+    }
+
+    impl Device for CountingDevice {
+        fn handle(&mut self, _ports: &mut [i32], _stack: &mut Vec<i32>, _memory: &mut [i32], _address_stack_depth: i32, _image_size: i32) {
+            self.hits.set(self.hits.get() + 1);
+        }
+    }
+
+    #[test]
+    fn wait_dispatches_every_triggered_port_with_a_bound_device() {
+        let mut cpu = CPU::from_image(&[0]);
+        let port1_hits = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        let port2_hits = ::std::rc::Rc::new(::std::cell::Cell::new(0));
+        cpu.register_device(1, Box::new(CountingDevice { hits: port1_hits.clone() }));
+        cpu.register_device(2, Box::new(CountingDevice { hits: port2_hits.clone() }));
+
+        cpu.ports[1] = 1;
+        cpu.ports[2] = 1;
+        assert!(cpu.dispatch_device());
+
+        assert_eq!(port1_hits.get(), 1);
+        assert_eq!(port2_hits.get(), 1);
+        assert_eq!(cpu.ports[1], 0);
+        assert_eq!(cpu.ports[2], 0);
+        assert_eq!(cpu.ports[0], 1);
+    }
+
+    #[test]
+    fn capability_device_reports_image_size_alongside_memory_and_stack_info() {
+        use device::CapabilityDevice;
+
+        let image = [1, 2, 3];
+        let mut cpu = CPU::from_image(&image);
+        cpu.register_device(5, Box::new(CapabilityDevice));
+
+        let info = cpu.get_info();
+        cpu.ports[5] = 1;
+        assert!(cpu.dispatch_device());
+
+        let (_, stack) = cpu.ports_and_stack();
+        assert_eq!(stack.pop(), Some(info.image_size));
+        assert_eq!(stack.pop(), Some(info.address_stack_depth));
+        assert_eq!(stack.pop(), Some(info.data_stack_depth));
+        assert_eq!(stack.pop(), Some(info.memory_size));
+        assert_eq!(info.image_size, image.len() as i32);
+    }
+
+    #[test]
+    fn save_snapshot_then_load_snapshot_round_trips_full_state() {
+        let mut cpu = CPU::from_image(&[1, 7, 1, 35, 16]); // LIT 7, LIT 35, ADD
+        assert!(cpu.next().is_ok());
+
+        let path = ::std::env::temp_dir().join("ngaro_cpu_snapshot_roundtrip_test.img");
+        cpu.save_snapshot(&path);
+        let restored = CPU::load_snapshot(&path);
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.ip, cpu.ip);
+        assert_eq!(&restored.ports[..], &cpu.ports[..]);
+        assert_eq!(restored.memory.memory_space, cpu.memory.memory_space);
+        assert_eq!(restored.memory.data_stack, cpu.memory.data_stack);
+        assert_eq!(restored.memory.address_stack, cpu.memory.address_stack);
+    }
 }
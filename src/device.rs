@@ -0,0 +1,158 @@
+//! A trait-based device bus for the Ngaro I/O ports.
+//!
+//! `CPU` used to expose nothing more than the raw `[i32; 12]` port array, so
+//! the only way a host could react to IN/OUT traffic was to interpret a
+//! bare `Wait` after the fact. `Device` lets an embedder register a handler
+//! against a specific port; `CPU`'s WAIT dispatch calls straight into it
+//! instead of stopping the VM, so adding a peripheral never touches the
+//! dispatch loop.
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+/// A peripheral bound to one Ngaro port. `handle` is called with the raw
+/// port array, the data stack, and the whole memory space, which is enough
+/// for both simple capability queries and devices that need to read/write
+/// buffers living in VM memory (file I/O, string buffers, ...).
+/// `address_stack_depth` and `image_size` are passed alongside because
+/// neither the address (return) stack depth nor the original image size
+/// (as opposed to the zero-padded memory size) is otherwise exposed
+/// outside `CPU`.
+pub trait Device {
+    fn handle(&mut self, ports: &mut [i32], stack: &mut Vec<i32>, memory: &mut [i32], address_stack_depth: i32, image_size: i32);
+}
+
+/// Capability query device for port 5: on trigger, pushes the memory size,
+/// data stack depth, address stack depth and image size onto the stack, in
+/// that order. This is the same information `CPU::get_info` gives a host
+/// directly, made available to the running image itself.
+pub struct CapabilityDevice;
+
+impl Device for CapabilityDevice {
+    fn handle(&mut self, _ports: &mut [i32], stack: &mut Vec<i32>, memory: &mut [i32], address_stack_depth: i32, image_size: i32) {
+        let data_stack_depth = stack.len() as i32;
+        stack.push(memory.len() as i32);
+        stack.push(data_stack_depth);
+        stack.push(address_stack_depth);
+        stack.push(image_size);
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::file::FileDevice;
+
+#[cfg(feature = "std")]
+mod file {
+    use std::fs::{File, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::cmp::max;
+
+    use super::Device;
+
+    fn read_cstring(memory: &[i32], addr: i32) -> String {
+        let mut path = String::new();
+        let mut i = addr as usize;
+        while let Some(&cell) = memory.get(i) {
+            if cell == 0 {
+                break;
+            }
+            path.push(cell as u8 as char);
+            i += 1;
+        }
+        path
+    }
+
+    /// File I/O device for port 4. The image drives it with a small
+    /// stack-based protocol: pop an opcode, then its arguments, then push
+    /// the result.
+    ///
+    /// - `0`: open  `( path-addr mode -- handle )`, mode 0=read 1=write
+    ///   (truncate) 2=append 3=read/write, handle -1 on failure.
+    /// - `1`: read  `( handle addr len -- bytes-read )`
+    /// - `2`: write `( handle addr len -- bytes-written )`
+    /// - `3`: close `( handle -- )`
+    /// - `4`: seek  `( handle offset -- position )`, position -1 on failure.
+    ///
+    /// `path`, and the read/write buffers, are read from/written to the
+    /// image's own memory as byte-valued cells.
+    pub struct FileDevice {
+        files: Vec<Option<File>>,
+    }
+
+    impl FileDevice {
+        pub fn new() -> FileDevice {
+            FileDevice { files: Vec::new() }
+        }
+    }
+
+    impl Device for FileDevice {
+        fn handle(&mut self, _ports: &mut [i32], stack: &mut Vec<i32>, memory: &mut [i32], _address_stack_depth: i32, _image_size: i32) {
+            let op = stack.pop().unwrap_or(0);
+            match op {
+                0 => { // open
+                    let mode = stack.pop().unwrap_or(0);
+                    let path_addr = stack.pop().unwrap_or(0);
+                    let path = read_cstring(memory, path_addr);
+                    let opened = match mode {
+                        1 => OpenOptions::new().write(true).create(true).truncate(true).open(&path),
+                        2 => OpenOptions::new().append(true).create(true).open(&path),
+                        3 => OpenOptions::new().read(true).write(true).create(true).open(&path),
+                        _ => OpenOptions::new().read(true).open(&path),
+                    };
+                    match opened {
+                        Ok(file) => {
+                            self.files.push(Some(file));
+                            stack.push((self.files.len() - 1) as i32);
+                        }
+                        Err(_) => stack.push(-1),
+                    }
+                }
+                1 => { // read
+                    let len = stack.pop().unwrap_or(0);
+                    let addr = stack.pop().unwrap_or(0);
+                    let handle = stack.pop().unwrap_or(-1);
+                    let read = self.files.get_mut(handle as usize).and_then(|f| f.as_mut()).map(|file| {
+                        let mut buf = vec![0u8; max(len, 0) as usize];
+                        let n = file.read(&mut buf).unwrap_or(0);
+                        for (i, byte) in buf[..n].iter().enumerate() {
+                            if let Some(cell) = memory.get_mut(addr as usize + i) {
+                                *cell = *byte as i32;
+                            }
+                        }
+                        n as i32
+                    }).unwrap_or(0);
+                    stack.push(read);
+                }
+                2 => { // write
+                    let len = stack.pop().unwrap_or(0);
+                    let addr = stack.pop().unwrap_or(0);
+                    let handle = stack.pop().unwrap_or(-1);
+                    let written = self.files.get_mut(handle as usize).and_then(|f| f.as_mut()).map(|file| {
+                        let buf: Vec<u8> = (0..len)
+                            .filter_map(|i| memory.get(addr as usize + i as usize).map(|&c| c as u8))
+                            .collect();
+                        file.write(&buf).unwrap_or(0) as i32
+                    }).unwrap_or(0);
+                    stack.push(written);
+                }
+                3 => { // close
+                    let handle = stack.pop().unwrap_or(-1);
+                    if let Some(slot) = self.files.get_mut(handle as usize) {
+                        *slot = None;
+                    }
+                }
+                4 => { // seek
+                    let offset = stack.pop().unwrap_or(0);
+                    let handle = stack.pop().unwrap_or(-1);
+                    let position = self.files.get_mut(handle as usize)
+                        .and_then(|f| f.as_mut())
+                        .and_then(|file| file.seek(SeekFrom::Start(max(offset, 0) as u64)).ok())
+                        .map(|p| p as i32)
+                        .unwrap_or(-1);
+                    stack.push(position);
+                }
+                _ => { }
+            }
+        }
+    }
+}
@@ -0,0 +1,10 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate byteorder;
+
+#[cfg(not(feature = "std"))]
+extern crate alloc;
+
+pub mod cpu;
+pub mod device;